@@ -0,0 +1,1015 @@
+//! Crate `walkdir` provides an efficient and platform independent way to
+//! recursively walk a directory.
+//!
+//! ```no_run
+//! use walkdir::WalkDir;
+//!
+//! for entry in WalkDir::new("foo") {
+//!     let entry = entry.unwrap();
+//!     println!("{}", entry.path().display());
+//! }
+//! ```
+
+#[cfg(test)]
+extern crate quickcheck;
+#[cfg(test)]
+extern crate rand;
+
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::error;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[cfg(test)]
+mod tests;
+
+/// The filesystem backend a `WalkDir` reads through.
+///
+/// `WalkDir::new` uses `RealFs`, which defers to `std::fs`. Implementing
+/// this trait for something else (an in-memory tree, say) lets the same
+/// traversal, loop-detection and pruning logic run against a fake
+/// filesystem, which is how this crate's own tests exercise it without
+/// touching disk.
+pub trait Fs {
+    /// The iterator returned by `read_dir`.
+    type ReadDir: Iterator<Item = io::Result<FsEntry>>;
+
+    /// Reads the entries of the directory at `path`.
+    fn read_dir(&self, path: &Path) -> io::Result<Self::ReadDir>;
+
+    /// Metadata for `path`, following a trailing symbolic link.
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Metadata for `path`, without following a trailing symbolic link.
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Reads the target of the symbolic link at `path`.
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+}
+
+/// A minimal, backend-independent view of a filesystem entry's type and
+/// identity.
+///
+/// This is what `Fs` implementations hand back instead of
+/// `std::fs::Metadata`, since that type has no public constructor and so
+/// can't be produced by a backend (like an in-memory tree) that isn't
+/// backed by a real `stat`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    is_dir: bool,
+    is_file: bool,
+    is_symlink: bool,
+    #[cfg(unix)]
+    dev_ino: (u64, u64),
+}
+
+impl Metadata {
+    /// Construct metadata directly, for `Fs` implementations that have no
+    /// real `std::fs::Metadata` to adapt.
+    ///
+    /// On Unix, the `(dev, ino)` pair defaults to `(0, 0)`; use
+    /// `with_ino` to give entries a distinct identity for loop detection.
+    #[cfg(unix)]
+    pub fn new(is_dir: bool, is_file: bool, is_symlink: bool) -> Metadata {
+        Metadata {
+            is_dir: is_dir,
+            is_file: is_file,
+            is_symlink: is_symlink,
+            dev_ino: (0, 0),
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn new(is_dir: bool, is_file: bool, is_symlink: bool) -> Metadata {
+        Metadata { is_dir: is_dir, is_file: is_file, is_symlink: is_symlink }
+    }
+
+    /// Attach a `(dev, ino)` identity, used by Unix loop detection.
+    #[cfg(unix)]
+    pub fn with_ino(mut self, dev: u64, ino: u64) -> Metadata {
+        self.dev_ino = (dev, ino);
+        self
+    }
+
+    #[cfg(unix)]
+    fn from_std(md: fs::Metadata) -> Metadata {
+        use std::os::unix::fs::MetadataExt;
+        Metadata {
+            is_dir: md.is_dir(),
+            is_file: md.is_file(),
+            is_symlink: md.file_type().is_symlink(),
+            dev_ino: (md.dev(), md.ino()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn from_std(md: fs::Metadata) -> Metadata {
+        Metadata {
+            is_dir: md.is_dir(),
+            is_file: md.is_file(),
+            is_symlink: md.file_type().is_symlink(),
+        }
+    }
+
+    /// Whether this entry is a directory.
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    /// Whether this entry is a regular file.
+    pub fn is_file(&self) -> bool {
+        self.is_file
+    }
+
+    /// Whether this entry is a symbolic link.
+    pub fn is_symlink(&self) -> bool {
+        self.is_symlink
+    }
+}
+
+/// One entry produced by `Fs::read_dir`: a path paired with the metadata
+/// the read already obtained for it (never following a trailing symlink).
+pub struct FsEntry {
+    path: PathBuf,
+    md: Metadata,
+}
+
+impl FsEntry {
+    /// Construct an entry directly, for `Fs` implementations that don't
+    /// read through a `std::fs::DirEntry`.
+    pub fn new(path: PathBuf, md: Metadata) -> FsEntry {
+        FsEntry { path: path, md: md }
+    }
+
+    /// The full path of this entry.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The metadata captured for this entry during the directory read.
+    pub fn metadata(&self) -> Metadata {
+        self.md
+    }
+}
+
+/// The default `Fs` implementation, backed by `std::fs`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RealFs;
+
+impl Fs for RealFs {
+    type ReadDir = RealReadDir;
+
+    fn read_dir(&self, path: &Path) -> io::Result<RealReadDir> {
+        Ok(RealReadDir(try!(fs::read_dir(path))))
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        Ok(Metadata::from_std(try!(fs::metadata(path))))
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        Ok(Metadata::from_std(try!(fs::symlink_metadata(path))))
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(path)
+    }
+}
+
+/// The `ReadDir` iterator used by `RealFs`.
+pub struct RealReadDir(fs::ReadDir);
+
+impl Iterator for RealReadDir {
+    type Item = io::Result<FsEntry>;
+
+    fn next(&mut self) -> Option<io::Result<FsEntry>> {
+        match self.0.next() {
+            None => None,
+            Some(Err(err)) => Some(Err(err)),
+            Some(Ok(ent)) => {
+                match ent.metadata() {
+                    Ok(md) => Some(Ok(FsEntry { path: ent.path(), md: Metadata::from_std(md) })),
+                    Err(err) => Some(Err(err)),
+                }
+            }
+        }
+    }
+}
+
+/// A builder to create an iterator for recursively walking a directory.
+///
+/// The sequential iterator returned by `into_iter` yields results in
+/// depth first order; a parallel walk (`threads`) does not. Directory entries are
+/// yielded but are never descended into unless they are read from the
+/// `Iterator` returned by `into_iter` (or, for a parallel walk, via
+/// `threads`).
+pub struct WalkDir<P, F = RealFs> {
+    root: P,
+    opts: WalkDirOptions,
+    filter: Option<Box<FnMut(&DirEntry) -> bool>>,
+    sort_by: Option<Box<FnMut(&DirEntry, &DirEntry) -> Ordering>>,
+    fs: F,
+}
+
+#[derive(Clone)]
+struct WalkDirOptions {
+    follow_links: bool,
+    contents_first: bool,
+}
+
+impl<P: AsRef<Path>> WalkDir<P, RealFs> {
+    /// Create a builder for a recursive directory iterator starting at
+    /// the file path `root`, reading through the real filesystem.
+    pub fn new(root: P) -> WalkDir<P, RealFs> {
+        WalkDir::with_fs(root, RealFs)
+    }
+
+    /// Turn this builder into a parallel walker that distributes
+    /// directory reads across `n` worker threads (`n` is clamped to at
+    /// least `1`).
+    ///
+    /// Unlike the sequential iterator returned by `into_iter`, a
+    /// `ParallelWalkDir` makes no guarantee about the order in which
+    /// entries are produced: each worker pulls pending directories off a
+    /// shared queue as soon as it's free, so entries from different
+    /// subtrees may interleave. It's only available on the real
+    /// filesystem, since its worker pool talks to `std::fs` directly.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `filter_entry` or `sort_by` was set on this builder.
+    /// Neither option is wired into the parallel path (the worker pool
+    /// dispatches directories across threads before any single thread
+    /// sees an entire directory's entries, so a global sort has nowhere
+    /// to apply, and the filter closures aren't required to be `Send`),
+    /// so silently dropping them would mean the walk simply ignores
+    /// options the caller asked for.
+    ///
+    /// Also panics if `contents_first` was set: `read_job_dir` yields
+    /// each directory entry as soon as it's read, with no notion of
+    /// waiting for its subtree, so `contents_first` would likewise be
+    /// silently ignored rather than applied.
+    pub fn threads(self, n: usize) -> ParallelWalkDir {
+        assert!(
+            self.filter.is_none() && self.sort_by.is_none(),
+            "WalkDir::threads: filter_entry/sort_by are not supported \
+             by the parallel walker"
+        );
+        assert!(
+            !self.opts.contents_first,
+            "WalkDir::threads: contents_first is not supported by the \
+             parallel walker"
+        );
+        ParallelWalkDir {
+            root: self.root.as_ref().to_path_buf(),
+            opts: self.opts,
+            threads: if n == 0 { 1 } else { n },
+        }
+    }
+}
+
+impl<P: AsRef<Path>, F: Fs> WalkDir<P, F> {
+    /// Create a builder for a recursive directory iterator starting at
+    /// the file path `root`, reading through `fs` instead of the real
+    /// filesystem.
+    pub fn with_fs(root: P, fs: F) -> WalkDir<P, F> {
+        WalkDir {
+            root: root,
+            opts: WalkDirOptions { follow_links: false, contents_first: false },
+            filter: None,
+            sort_by: None,
+            fs: fs,
+        }
+    }
+
+    /// When `yes` is `true`, symbolic links are followed as if they were
+    /// normal directories and files. If a symbolic link is broken or is
+    /// involved in a loop, an error is yielded.
+    ///
+    /// By default, symbolic links are not followed.
+    pub fn follow_links(mut self, yes: bool) -> Self {
+        self.opts.follow_links = yes;
+        self
+    }
+
+    /// When `yes` is `true`, a directory's contents are yielded before
+    /// the directory entry itself, i.e., the walk becomes post-order
+    /// instead of the default pre-order. This is implemented by
+    /// buffering each directory's `DirEntry` and emitting it once its
+    /// subtree has been fully walked, which lets callers compute
+    /// recursive directory sizes or delete a tree bottom-up in a single
+    /// pass.
+    ///
+    /// By default, a directory is yielded before its contents.
+    pub fn contents_first(mut self, yes: bool) -> Self {
+        self.opts.contents_first = yes;
+        self
+    }
+
+    /// Set a predicate that's consulted before descending into a
+    /// directory. When `filter` returns `false` for a directory entry,
+    /// that entry is still yielded, but `read_dir` is never called on
+    /// it, so the cost of walking the pruned subtree is avoided
+    /// entirely (unlike filtering the resulting `Iterator`, which only
+    /// discards entries after they've already been read).
+    pub fn filter_entry<Flt>(mut self, filter: Flt) -> Self
+        where Flt: FnMut(&DirEntry) -> bool + 'static
+    {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Sort each directory's entries with `cmp` before yielding them, so
+    /// the walk produces a stable, reproducible order instead of
+    /// whatever order the filesystem happens to return.
+    ///
+    /// This is implemented by collecting each `read_dir` batch into a
+    /// `Vec<DirEntry>`, sorting it, then draining it in order, so it
+    /// costs an allocation per directory rather than being free like the
+    /// default unsorted walk.
+    pub fn sort_by<C>(mut self, cmp: C) -> Self
+        where C: FnMut(&DirEntry, &DirEntry) -> Ordering + 'static
+    {
+        self.sort_by = Some(Box::new(cmp));
+        self
+    }
+
+    /// A convenience for `sort_by` that orders entries lexicographically
+    /// by file name.
+    pub fn sort_by_file_name(self) -> Self {
+        self.sort_by(|a, b| a.file_name().cmp(b.file_name()))
+    }
+
+    /// Turn this builder into an iterator of `Event`s, which makes a
+    /// directory's enter/exit points explicit instead of leaving callers
+    /// to track depth themselves to notice them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `contents_first` was set on this builder. `EventIter`
+    /// infers `Dir`/`Exit` brackets by assuming entries arrive in
+    /// pre-order (a directory's `Dir` event before any of its
+    /// descendants), which is exactly what `contents_first` inverts, so
+    /// the combination would silently produce a corrupted event stream
+    /// rather than a differently-ordered one.
+    pub fn into_event_iter(self) -> EventIter<F> {
+        assert!(
+            !self.opts.contents_first,
+            "WalkDir::into_event_iter: contents_first is not supported \
+             by EventIter"
+        );
+        EventIter { depth: 0, it: self.into_iter(), next: None }
+    }
+}
+
+impl<P: AsRef<Path>, F: Fs> IntoIterator for WalkDir<P, F> {
+    type Item = Result<DirEntry, WalkDirError>;
+    type IntoIter = WalkDirIter<F>;
+
+    fn into_iter(self) -> WalkDirIter<F> {
+        WalkDirIter {
+            fs: self.fs,
+            opts: self.opts,
+            filter: self.filter,
+            sort_by: self.sort_by,
+            start: Some(self.root.as_ref().to_path_buf()),
+            stack: vec![],
+            ancestors: vec![],
+            pending: vec![],
+        }
+    }
+}
+
+/// An iterator for recursively descending into a directory.
+///
+/// This yields every file and directory found underneath the root given
+/// to `WalkDir::new`, but never the root itself. Directories are yielded
+/// in pre-order unless `WalkDir::contents_first` was set, in which case
+/// they're yielded after their contents.
+pub struct WalkDirIter<F: Fs> {
+    fs: F,
+    opts: WalkDirOptions,
+    filter: Option<Box<FnMut(&DirEntry) -> bool>>,
+    sort_by: Option<Box<FnMut(&DirEntry, &DirEntry) -> Ordering>>,
+    start: Option<PathBuf>,
+    stack: Vec<DirFrame<F::ReadDir>>,
+    /// Directory entries whose emission has been deferred until their
+    /// subtree finishes walking; only used when `contents_first` is set.
+    /// One entry per directory currently open below the root, so its
+    /// length tracks `stack.len() - 1`.
+    pending: Vec<DirEntry>,
+    ancestors: Vec<Ancestor>,
+}
+
+/// One open directory on the stack: either read lazily, straight off the
+/// `Fs`, or (under `WalkDir::sort_by`) fully collected and sorted ahead
+/// of time.
+enum DirFrame<R> {
+    Live(R),
+    Sorted(::std::vec::IntoIter<io::Result<FsEntry>>),
+}
+
+impl<R: Iterator<Item = io::Result<FsEntry>>> Iterator for DirFrame<R> {
+    type Item = io::Result<FsEntry>;
+
+    fn next(&mut self) -> Option<io::Result<FsEntry>> {
+        match *self {
+            DirFrame::Live(ref mut rd) => rd.next(),
+            DirFrame::Sorted(ref mut it) => it.next(),
+        }
+    }
+}
+
+/// An ancestor directory on the current path from the root to the entry
+/// being visited, used to detect symlink loops.
+///
+/// On Unix, ancestors are identified by `(dev, ino)` rather than by path,
+/// so that loops spanning renamed or relative symlinks, and hard-linked
+/// directories, are caught even when a path comparison would miss them.
+#[derive(Clone)]
+struct Ancestor {
+    path: PathBuf,
+    #[cfg(unix)]
+    dev_ino: (u64, u64),
+}
+
+impl Ancestor {
+    #[cfg(unix)]
+    fn new(path: &Path, md: &Metadata) -> Ancestor {
+        Ancestor { path: path.to_path_buf(), dev_ino: md.dev_ino }
+    }
+
+    #[cfg(not(unix))]
+    fn new(path: &Path, _md: &Metadata) -> Ancestor {
+        Ancestor { path: path.to_path_buf() }
+    }
+
+    #[cfg(unix)]
+    fn is_same(&self, _path: &Path, md: &Metadata) -> bool {
+        self.dev_ino == md.dev_ino
+    }
+
+    #[cfg(not(unix))]
+    fn is_same(&self, path: &Path, _md: &Metadata) -> bool {
+        fs::canonicalize(path).map(|c| c == self.path).unwrap_or(false)
+    }
+
+    /// Returns the ancestor that `path`/`md` would re-enter, if any.
+    fn find<'a>(
+        ancestors: &'a [Ancestor],
+        path: &Path,
+        md: &Metadata,
+    ) -> Option<&'a Ancestor> {
+        ancestors.iter().find(|a| a.is_same(path, md))
+    }
+}
+
+impl<F: Fs> WalkDirIter<F> {
+    /// Returns the number of directories (including any followed
+    /// symlinks) currently open on the walk's stack, the root included;
+    /// this is `0` before the first call to `next`.
+    ///
+    /// While the innermost open directory still has entries left, this
+    /// is the depth its next entry will have (a direct child of the root
+    /// has depth `1`). It is not updated until `next` pops a finished
+    /// directory, and a directory deferred by `contents_first` is
+    /// yielded at one less than this value.
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    fn push(&mut self, path: PathBuf, md: &Metadata) -> io::Result<()> {
+        let rd = try!(self.fs.read_dir(&path));
+        let frame = match self.sort_by {
+            None => DirFrame::Live(rd),
+            Some(ref mut cmp) => {
+                let depth = self.stack.len() + 1;
+                let mut dents = vec![];
+                for ent in rd {
+                    let ent = try!(ent);
+                    dents.push(DirEntry::new(
+                        ent.path().to_path_buf(), ent.metadata(), depth));
+                }
+                dents.sort_by(|a, b| cmp(a, b));
+                let entries: Vec<io::Result<FsEntry>> = dents.into_iter()
+                    .map(|d| Ok(FsEntry::new(d.path, d.md)))
+                    .collect();
+                DirFrame::Sorted(entries.into_iter())
+            }
+        };
+        self.ancestors.push(Ancestor::new(&path, md));
+        self.stack.push(frame);
+        Ok(())
+    }
+
+    /// Pops the innermost open directory. If it has a deferred
+    /// `contents_first` entry waiting on it, that entry is returned so
+    /// the caller can yield it now that its subtree is done.
+    fn pop(&mut self) -> Option<DirEntry> {
+        self.stack.pop();
+        self.ancestors.pop();
+        if self.opts.contents_first { self.pending.pop() } else { None }
+    }
+
+    /// Either returns a directory entry right away, or, under
+    /// `contents_first`, defers it until its subtree has been walked.
+    fn yield_dir(&mut self, dent: DirEntry) -> Option<Result<DirEntry, WalkDirError>> {
+        if self.opts.contents_first {
+            self.pending.push(dent);
+            None
+        } else {
+            Some(Ok(dent))
+        }
+    }
+}
+
+impl<F: Fs> Iterator for WalkDirIter<F> {
+    type Item = Result<DirEntry, WalkDirError>;
+
+    fn next(&mut self) -> Option<Result<DirEntry, WalkDirError>> {
+        if let Some(start) = self.start.take() {
+            let md = match self.fs.metadata(&start) {
+                Ok(md) => md,
+                Err(err) => return Some(Err(WalkDirError::from_path(start, err))),
+            };
+            if let Err(err) = self.push(start.clone(), &md) {
+                return Some(Err(WalkDirError::from_path(start, err)));
+            }
+        }
+        loop {
+            let next = match self.stack.last_mut() {
+                None => return None,
+                Some(rd) => rd.next(),
+            };
+            match next {
+                None => {
+                    if let Some(dent) = self.pop() {
+                        return Some(Ok(dent));
+                    }
+                    if self.stack.is_empty() {
+                        return None;
+                    }
+                }
+                Some(Err(err)) => {
+                    return Some(Err(WalkDirError::Io { path: None, err: err }));
+                }
+                Some(Ok(ent)) => {
+                    let path = ent.path().to_path_buf();
+                    let md = ent.metadata();
+                    let depth = self.stack.len();
+                    if md.is_dir() {
+                        let dent = DirEntry::new(path.clone(), md, depth);
+                        if !self.filter.as_mut().map_or(true, |f| f(&dent)) {
+                            return Some(Ok(dent));
+                        }
+                        if let Err(err) = self.push(path, &md) {
+                            return Some(Err(
+                                WalkDirError::from_path(dent.path().to_path_buf(), err)));
+                        }
+                        if let Some(ret) = self.yield_dir(dent) {
+                            return Some(ret);
+                        }
+                    } else if self.opts.follow_links && md.is_symlink() {
+                        let target_md = match self.fs.metadata(&path) {
+                            Ok(md) => md,
+                            Err(err) => {
+                                return Some(Err(WalkDirError::from_path(path, err)));
+                            }
+                        };
+                        if !target_md.is_dir() {
+                            return Some(Ok(DirEntry::new(path, md, depth)));
+                        }
+                        // Followed this far, the entry is being treated
+                        // as a directory: cache the resolved metadata
+                        // (not the symlink's own lstat-style `md`) so
+                        // `file_type()`/`metadata()` agree with the walk
+                        // that a descent happened here.
+                        let dent = DirEntry::new(path.clone(), target_md, depth);
+                        if !self.filter.as_mut().map_or(true, |f| f(&dent)) {
+                            return Some(Ok(dent));
+                        }
+                        if let Some(ancestor) = Ancestor::find(&self.ancestors, &path, &target_md) {
+                            return Some(Err(WalkDirError::Loop {
+                                ancestor: ancestor.path.clone(),
+                                child: path,
+                            }));
+                        }
+                        if let Err(err) = self.push(path, &target_md) {
+                            return Some(Err(
+                                WalkDirError::from_path(dent.path().to_path_buf(), err)));
+                        }
+                        if let Some(ret) = self.yield_dir(dent) {
+                            return Some(ret);
+                        }
+                    } else {
+                        return Some(Ok(DirEntry::new(path, md, depth)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// An event produced by `EventIter`, making a directory's enter and exit
+/// points explicit rather than leaving callers to infer them from depth.
+pub enum Event {
+    /// Entering a directory; its contents are yielded next.
+    Dir(DirEntry),
+    /// A non-directory entry, including any symlink that isn't followed
+    /// as a directory (with `follow_links`, a symlink to a file is still
+    /// yielded here).
+    File(DirEntry),
+    /// Leaving the directory most recently entered via `Event::Dir`.
+    Exit,
+}
+
+/// An iterator over `WalkDir::into_event_iter` that synthesizes an
+/// `Event::Exit` whenever it's about to leave a directory, so that tree
+/// builders (size aggregators, `du`-style tools, file explorers) don't
+/// each have to reinvent tracking that from `DirEntry::depth`.
+pub struct EventIter<F: Fs = RealFs> {
+    depth: usize,
+    it: WalkDirIter<F>,
+    next: Option<Result<DirEntry, WalkDirError>>,
+}
+
+impl<F: Fs> EventIter<F> {
+    /// The depth of the directory currently open, i.e. entered via
+    /// `Event::Dir` and not yet closed by `Event::Exit` (`0` at the root).
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+}
+
+impl<F: Fs> Iterator for EventIter<F> {
+    type Item = Result<Event, WalkDirError>;
+
+    fn next(&mut self) -> Option<Result<Event, WalkDirError>> {
+        let dent = self.next.take().or_else(|| self.it.next());
+        match dent {
+            None => {
+                // The walker is done, but directories still open (their
+                // `Exit` was never due because nothing followed them)
+                // still need to be closed out one at a time.
+                if self.depth > 0 {
+                    self.depth -= 1;
+                    Some(Ok(Event::Exit))
+                } else {
+                    None
+                }
+            }
+            Some(Err(err)) => Some(Err(err)),
+            Some(Ok(dent)) => {
+                // A child of the currently open directory has depth
+                // `self.depth + 1`; anything shallower means one or more
+                // directories closed without a next sibling to notice it
+                // (e.g. an empty trailing subdirectory), so `dent` is
+                // deferred until enough `Exit`s have been emitted. This
+                // must compare `dent`'s own depth, not the walker's raw
+                // stack size, since the walker has already silently
+                // popped past any directories with no remaining entries.
+                if dent.depth() <= self.depth {
+                    self.depth -= 1;
+                    self.next = Some(Ok(dent));
+                    return Some(Ok(Event::Exit));
+                }
+                match dent.file_type() {
+                    Err(err) => Some(Err(WalkDirError::from_path(
+                        dent.path().to_path_buf(), err))),
+                    Ok(md) => {
+                        if md.is_dir() {
+                            self.depth = dent.depth();
+                            Some(Ok(Event::Dir(dent)))
+                        } else {
+                            Some(Ok(Event::File(dent)))
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A directory entry yielded by a walk.
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    path: PathBuf,
+    md: Metadata,
+    depth: usize,
+}
+
+impl DirEntry {
+    fn new(path: PathBuf, md: Metadata, depth: usize) -> DirEntry {
+        DirEntry { path: path, md: md, depth: depth }
+    }
+
+    /// The full path that this entry represents.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The file name of this entry, relative to its parent directory.
+    pub fn file_name(&self) -> &OsStr {
+        self.path.file_name().unwrap_or(self.path.as_os_str())
+    }
+
+    /// The file type of this entry, as determined by the directory read
+    /// that produced it (symbolic links are not followed, unless
+    /// `follow_links` caused this entry to be descended into as a
+    /// directory, in which case this reflects the resolved target).
+    ///
+    /// This is cached on the entry, so repeated calls never re-`stat`.
+    pub fn file_type(&self) -> io::Result<Metadata> {
+        Ok(self.md)
+    }
+
+    /// The metadata for this entry, as determined by the directory read
+    /// that produced it (symbolic links are not followed, unless
+    /// `follow_links` caused this entry to be descended into as a
+    /// directory, in which case this reflects the resolved target).
+    ///
+    /// Like `file_type`, this is simply cached on the entry.
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        Ok(self.md)
+    }
+
+    /// The depth of this entry relative to the root of the walk that
+    /// produced it. A direct child of the root has depth `1`.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+}
+
+/// Extension trait for `DirEntry` that exposes Unix-specific fields
+/// already captured from the directory read.
+#[cfg(unix)]
+pub trait DirEntryExt {
+    /// Returns the inode number from the `Metadata` cached on this entry
+    /// when it was read, so calling it does no additional `stat`.
+    ///
+    /// With `RealFs` this is the `st_ino` of the metadata the read
+    /// obtained, not the `d_ino` field of the directory entry. For a
+    /// symlink that `follow_links` descended into, it is the inode of
+    /// the target directory, not of the link.
+    fn ino(&self) -> u64;
+}
+
+#[cfg(unix)]
+impl DirEntryExt for DirEntry {
+    fn ino(&self) -> u64 {
+        self.md.dev_ino.1
+    }
+}
+
+/// An error produced by walking a directory.
+#[derive(Debug)]
+pub enum WalkDirError {
+    /// An I/O error, optionally attached to the path that caused it.
+    Io { path: Option<PathBuf>, err: io::Error },
+    /// A symbolic link loop was detected while following links: `child`
+    /// is the symbolic link that points back to the directory at
+    /// `ancestor`, somewhere above it on the current path.
+    Loop { ancestor: PathBuf, child: PathBuf },
+}
+
+impl WalkDirError {
+    fn from_path(path: PathBuf, err: io::Error) -> WalkDirError {
+        WalkDirError::Io { path: Some(path), err: err }
+    }
+}
+
+impl fmt::Display for WalkDirError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WalkDirError::Io { path: Some(ref path), ref err } => {
+                write!(f, "IO error at {}: {}", path.display(), err)
+            }
+            WalkDirError::Io { path: None, ref err } => {
+                write!(f, "IO error: {}", err)
+            }
+            WalkDirError::Loop { ref ancestor, ref child } => {
+                write!(
+                    f, "symlink loop: {} points back to {}",
+                    child.display(), ancestor.display())
+            }
+        }
+    }
+}
+
+impl error::Error for WalkDirError {
+    fn description(&self) -> &str {
+        match *self {
+            WalkDirError::Io { ref err, .. } => err.description(),
+            WalkDirError::Loop { .. } => "symlink loop detected",
+        }
+    }
+}
+
+impl From<WalkDirError> for io::Error {
+    fn from(err: WalkDirError) -> io::Error {
+        match err {
+            WalkDirError::Io { err, .. } => err,
+            WalkDirError::Loop { .. } => {
+                io::Error::new(io::ErrorKind::Other, err.to_string())
+            }
+        }
+    }
+}
+
+/// A parallel, work-stealing directory walker built via `WalkDir::threads`.
+///
+/// Each worker thread pops a pending directory off a shared deque, reads
+/// it, emits every entry it finds and pushes any subdirectories back onto
+/// the deque for some worker (possibly a different one) to pick up.
+/// Because entries from different subtrees can interleave, `DirEntry`
+/// carries its own `depth`, and errors are reported per-entry rather than
+/// through iterator state. Each pending directory carries its own chain
+/// of open ancestors, so a followed symlink that loops back on itself is
+/// detected the same way it is by the sequential walker, no matter which
+/// worker ends up reading it.
+pub struct ParallelWalkDir {
+    root: PathBuf,
+    opts: WalkDirOptions,
+    threads: usize,
+}
+
+/// A pending directory read: its path, depth, and the chain of
+/// currently-open ancestor directories (by `(dev, ino)` identity) that a
+/// followed symlink inside it must be checked against, so that a loop
+/// can be detected no matter which worker thread ends up reading it.
+type Job = (PathBuf, usize, Vec<Ancestor>);
+
+impl ParallelWalkDir {
+    /// Run the walk, invoking `f` with every entry (and any error) as
+    /// they're produced. `f` may be called concurrently from multiple
+    /// worker threads and must not block on the walk making progress.
+    ///
+    /// This call blocks until the entire tree has been walked.
+    pub fn run<F>(self, f: F)
+        where F: Fn(Result<DirEntry, WalkDirError>) + Send + Sync + 'static
+    {
+        for handle in self.spawn(Arc::new(f)) {
+            let _ = handle.join();
+        }
+    }
+
+    /// Spawn the walk in the background and return an iterator that
+    /// receives entries as they arrive over a channel.
+    ///
+    /// Unlike `run`, this does not block: the returned iterator can be
+    /// drained while the walk is still in progress.
+    pub fn into_iter(self) -> ParallelWalkDirIter {
+        let (tx, rx) = mpsc::channel();
+        let handles = self.spawn(Arc::new(move |ent| { let _ = tx.send(ent); }));
+        thread::spawn(move || {
+            for handle in handles {
+                let _ = handle.join();
+            }
+        });
+        ParallelWalkDirIter { rx: rx }
+    }
+
+    fn spawn<F>(self, f: Arc<F>) -> Vec<thread::JoinHandle<()>>
+        where F: Fn(Result<DirEntry, WalkDirError>) + Send + Sync + 'static
+    {
+        let deque = Arc::new(Mutex::new(VecDeque::new()));
+        let pending = Arc::new(AtomicUsize::new(1));
+        let root_ancestors = fs::metadata(&self.root)
+            .map(|md| vec![Ancestor::new(&self.root, &Metadata::from_std(md))])
+            .unwrap_or_default();
+        deque.lock().unwrap().push_back((self.root, 1, root_ancestors));
+
+        let follow_links = self.opts.follow_links;
+        (0..self.threads).map(|_| {
+            let deque = deque.clone();
+            let pending = pending.clone();
+            let f = f.clone();
+            thread::spawn(move || worker(deque, pending, follow_links, f))
+        }).collect()
+    }
+}
+
+impl IntoIterator for ParallelWalkDir {
+    type Item = Result<DirEntry, WalkDirError>;
+    type IntoIter = ParallelWalkDirIter;
+
+    fn into_iter(self) -> ParallelWalkDirIter {
+        ParallelWalkDir::into_iter(self)
+    }
+}
+
+/// An iterator over the entries produced by a `ParallelWalkDir`.
+pub struct ParallelWalkDirIter {
+    rx: mpsc::Receiver<Result<DirEntry, WalkDirError>>,
+}
+
+impl Iterator for ParallelWalkDirIter {
+    type Item = Result<DirEntry, WalkDirError>;
+
+    fn next(&mut self) -> Option<Result<DirEntry, WalkDirError>> {
+        self.rx.recv().ok()
+    }
+}
+
+fn worker<F>(
+    deque: Arc<Mutex<VecDeque<Job>>>,
+    pending: Arc<AtomicUsize>,
+    follow_links: bool,
+    f: Arc<F>,
+) where F: Fn(Result<DirEntry, WalkDirError>) + Send + Sync + 'static {
+    loop {
+        let job = deque.lock().unwrap().pop_front();
+        let (dir, depth, ancestors) = match job {
+            Some(job) => job,
+            None => {
+                if pending.load(AtomicOrdering::SeqCst) == 0 {
+                    return;
+                }
+                thread::yield_now();
+                continue;
+            }
+        };
+        read_job_dir(&dir, depth, &ancestors, follow_links, &deque, &pending, &f);
+        pending.fetch_sub(1, AtomicOrdering::SeqCst);
+    }
+}
+
+fn read_job_dir<F>(
+    dir: &Path,
+    depth: usize,
+    ancestors: &[Ancestor],
+    follow_links: bool,
+    deque: &Arc<Mutex<VecDeque<Job>>>,
+    pending: &Arc<AtomicUsize>,
+    f: &Arc<F>,
+) where F: Fn(Result<DirEntry, WalkDirError>) + Send + Sync + 'static {
+    let rd = match fs::read_dir(dir) {
+        Ok(rd) => rd,
+        Err(err) => {
+            f(Err(WalkDirError::from_path(dir.to_path_buf(), err)));
+            return;
+        }
+    };
+    for ent in rd {
+        let ent = match ent {
+            Ok(ent) => ent,
+            Err(err) => {
+                f(Err(WalkDirError::Io { path: None, err: err }));
+                continue;
+            }
+        };
+        let path = ent.path();
+        let md = match ent.metadata() {
+            Ok(md) => Metadata::from_std(md),
+            Err(err) => {
+                f(Err(WalkDirError::from_path(path, err)));
+                continue;
+            }
+        };
+        // For a followed symlink, `dir_md` becomes the resolved target's
+        // metadata (consistent with the sequential walker and with
+        // `DirEntry::file_type`/`metadata` caching what the walk treated
+        // the entry as), and a stat error is reported rather than
+        // silently treated as "not a directory" (which would otherwise
+        // mask e.g. `ELOOP`).
+        let mut dir_md = md;
+        let mut is_dir = md.is_dir();
+        if !is_dir && follow_links && md.is_symlink() {
+            match fs::metadata(&path) {
+                Ok(target_md) => {
+                    let target_md = Metadata::from_std(target_md);
+                    if target_md.is_dir() {
+                        if let Some(ancestor) = Ancestor::find(ancestors, &path, &target_md) {
+                            f(Err(WalkDirError::Loop {
+                                ancestor: ancestor.path.clone(),
+                                child: path,
+                            }));
+                            continue;
+                        }
+                        is_dir = true;
+                        dir_md = target_md;
+                    }
+                }
+                Err(err) => {
+                    f(Err(WalkDirError::from_path(path, err)));
+                    continue;
+                }
+            }
+        }
+        if is_dir {
+            let mut child_ancestors = ancestors.to_vec();
+            child_ancestors.push(Ancestor::new(&path, &dir_md));
+            pending.fetch_add(1, AtomicOrdering::SeqCst);
+            deque.lock().unwrap().push_back((path.clone(), depth + 1, child_ancestors));
+        }
+        f(Ok(DirEntry::new(path, dir_md, depth)));
+    }
+}
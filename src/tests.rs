@@ -1,5 +1,6 @@
 #![allow(dead_code, unused_imports)]
 
+use std::collections::HashMap;
 use std::env;
 use std::fmt;
 use std::fs::{self, File};
@@ -9,7 +10,9 @@ use std::path::{Path, PathBuf};
 use quickcheck::{Arbitrary, Gen, QuickCheck, StdGen};
 use rand::{self, Rng};
 
-use super::{DirEntry, WalkDir, WalkDirError, WalkDirIter};
+#[cfg(unix)]
+use super::DirEntryExt;
+use super::{DirEntry, Event, EventIter, Fs, FsEntry, Metadata, RealFs, WalkDir, WalkDirError};
 
 #[derive(Clone, Eq, Ord, PartialEq, PartialOrd)]
 enum Tree {
@@ -29,19 +32,19 @@ impl Tree {
     ) -> io::Result<Tree>
     where P: AsRef<Path>, F: FnOnce(WalkDir<P>) -> WalkDir<P> {
         let mut stack = vec![Tree::Dir(p.as_ref().to_path_buf(), vec![])];
-        let it: WalkEventIter = f(WalkDir::new(p)).into();
+        let it: EventIter = f(WalkDir::new(p)).into_event_iter();
         for ev in it {
             match try!(ev) {
-                WalkEvent::Exit => {
+                Event::Exit => {
                     let tree = stack.pop().unwrap();
                     stack.last_mut().unwrap().children_mut().push(tree);
                 }
-                WalkEvent::Dir(dent) => {
+                Event::Dir(dent) => {
                     stack.push(Tree::Dir(pb(dent.file_name()), vec![]));
                 }
-                WalkEvent::File(dent) => {
+                Event::File(dent) => {
                     let node = if try!(dent.file_type()).is_symlink() {
-                        let src = try!(fs::read_link(dent.path()));
+                        let src = try!(RealFs.read_link(dent.path()));
                         let dst = pb(dent.file_name());
                         Tree::Symlink(src, dst)
                     } else {
@@ -55,6 +58,74 @@ impl Tree {
         Ok(stack.pop().unwrap())
     }
 
+    /// Reassembles a (possibly unordered) stream of entries into a
+    /// `Tree`, keyed off of each entry's full path rather than emission
+    /// order. This is what lets `from_parallel_walk` below, where
+    /// entries from different subtrees interleave, build the same kind
+    /// of `Tree` that `from_walk_with` does.
+    fn from_entries<I, G>(root: &Path, it: I, fsys: &G) -> io::Result<Tree>
+        where I: Iterator<Item = Result<DirEntry, WalkDirError>>, G: Fs
+    {
+        let mut children: HashMap<PathBuf, Vec<Tree>> = HashMap::new();
+        for ent in it {
+            let dent = try!(ent.map_err(io::Error::from));
+            let parent = dent.path().parent().unwrap().to_path_buf();
+            let name = pb(dent.file_name());
+            let ty = try!(dent.file_type());
+            let node = if ty.is_symlink() {
+                let src = try!(fsys.read_link(dent.path()));
+                Tree::Symlink(src, name)
+            } else if ty.is_dir() {
+                Tree::Dir(name, vec![])
+            } else {
+                Tree::File(name)
+            };
+            children.entry(parent).or_insert_with(Vec::new).push(node);
+        }
+        let mut top = children.remove(root).unwrap_or_else(Vec::new);
+        for c in &mut top {
+            if let Tree::Dir(ref name, ref mut grandchildren) = *c {
+                *grandchildren = match Tree::assemble(&root.join(name), &mut children) {
+                    Tree::Dir(_, gc) => gc,
+                    _ => unreachable!(),
+                };
+            }
+        }
+        Ok(Tree::Dir(root.to_path_buf(), top))
+    }
+
+    /// Walks `p` with a `ParallelWalkDir` using `threads` workers.
+    fn from_parallel_walk<P: AsRef<Path>>(
+        p: P,
+        threads: usize,
+    ) -> io::Result<Tree> {
+        let root = p.as_ref().to_path_buf();
+        Tree::from_entries(&root, WalkDir::new(&root).threads(threads).into_iter(), &RealFs)
+    }
+
+    /// Walks `p` sequentially with `filter` wired up via
+    /// `WalkDir::filter_entry`, pruning whatever subtrees it rejects.
+    fn from_filtered_walk<P, F>(p: P, filter: F) -> io::Result<Tree>
+        where P: AsRef<Path>, F: FnMut(&DirEntry) -> bool + 'static
+    {
+        let root = p.as_ref().to_path_buf();
+        Tree::from_entries(
+            &root, WalkDir::new(&root).filter_entry(filter).into_iter(), &RealFs)
+    }
+
+    fn assemble(path: &Path, children: &mut HashMap<PathBuf, Vec<Tree>>) -> Tree {
+        let mut cs = children.remove(path).unwrap_or_else(Vec::new);
+        for c in &mut cs {
+            if let Tree::Dir(ref name, ref mut grandchildren) = *c {
+                *grandchildren = match Tree::assemble(&path.join(name), children) {
+                    Tree::Dir(_, gc) => gc,
+                    _ => unreachable!(),
+                };
+            }
+        }
+        Tree::Dir(pb(path.file_name().unwrap()), cs)
+    }
+
     fn name(&self) -> &Path {
         match *self {
             Tree::Dir(ref pb, _) => pb,
@@ -118,6 +189,32 @@ impl Tree {
         }
     }
 
+    /// The reference model for `WalkDir::filter_entry`: every directory
+    /// at `depth >= max_depth` (the root's direct children are at depth
+    /// `1`, matching `DirEntry::depth`) keeps itself but has its
+    /// children pruned away, since the walker would never have
+    /// descended into it.
+    fn pruned_at_depth(&self, max_depth: usize, depth: usize) -> Tree {
+        match *self {
+            Tree::Symlink(ref src, ref dst) => {
+                Tree::Symlink(src.clone(), dst.clone())
+            }
+            Tree::File(ref p) => {
+                Tree::File(p.clone())
+            }
+            Tree::Dir(ref p, ref cs) => {
+                if depth >= max_depth {
+                    Tree::Dir(p.clone(), vec![])
+                } else {
+                    let cs = cs.iter()
+                               .map(|c| c.pruned_at_depth(max_depth, depth + 1))
+                               .collect();
+                    Tree::Dir(p.clone(), cs)
+                }
+            }
+        }
+    }
+
     fn dedup(&self) -> Tree {
         match *self {
             Tree::Symlink(ref src, ref dst) => {
@@ -168,7 +265,15 @@ impl Tree {
 
         let name = pb(NonEmptyAscii::arbitrary(g).0);
         if depth == 0 {
-            Tree::File(name)
+            // A symlink's target is just the text stored in the link,
+            // never resolved here, so it doesn't need to name anything
+            // that actually exists in the generated tree.
+            if g.gen_weighted_bool(4) {
+                let target = pb(NonEmptyAscii::arbitrary(g).0);
+                Tree::Symlink(target, name)
+            } else {
+                Tree::File(name)
+            }
         } else {
             let children: Vec<Tree> =
                 (0..g.gen_range(0, 5))
@@ -179,6 +284,122 @@ impl Tree {
     }
 }
 
+/// An in-memory `Fs` backed directly by a `Tree`, so traversal logic can
+/// be exercised without creating a `TempDir` on disk. `root` is the
+/// virtual path at which `tree` (itself a `Tree::Dir`) is mounted.
+#[derive(Clone)]
+struct MemFs {
+    root: PathBuf,
+    tree: Tree,
+}
+
+impl MemFs {
+    fn new(root: PathBuf, tree: Tree) -> MemFs {
+        MemFs { root: root, tree: tree }
+    }
+
+    fn lookup(&self, path: &Path) -> io::Result<&Tree> {
+        let rel = match path.strip_prefix(&self.root) {
+            Ok(rel) => rel,
+            Err(_) => return Err(not_found()),
+        };
+        let mut node = &self.tree;
+        for comp in rel.components() {
+            node = match *node {
+                Tree::Dir(_, ref children) => {
+                    match children.iter().find(|c| c.name().as_os_str() == comp.as_os_str()) {
+                        Some(c) => c,
+                        None => return Err(not_found()),
+                    }
+                }
+                _ => return Err(not_found()),
+            };
+        }
+        Ok(node)
+    }
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "no such entry in MemFs")
+}
+
+fn mem_metadata(node: &Tree, path: &Path) -> Metadata {
+    let md = match *node {
+        Tree::Dir(..) => Metadata::new(true, false, false),
+        Tree::File(_) => Metadata::new(false, true, false),
+        Tree::Symlink(..) => Metadata::new(false, false, true),
+    };
+    #[cfg(unix)]
+    let md = md.with_ino(0, path_ino(path));
+    md
+}
+
+#[cfg(unix)]
+fn path_ino(path: &Path) -> u64 {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    let mut h = DefaultHasher::new();
+    path.hash(&mut h);
+    h.finish()
+}
+
+struct MemReadDir {
+    base: PathBuf,
+    entries: Vec<Tree>,
+    pos: usize,
+}
+
+impl Iterator for MemReadDir {
+    type Item = io::Result<FsEntry>;
+
+    fn next(&mut self) -> Option<io::Result<FsEntry>> {
+        if self.pos >= self.entries.len() {
+            return None;
+        }
+        let child = self.entries[self.pos].clone();
+        self.pos += 1;
+        let path = self.base.join(child.name());
+        let md = mem_metadata(&child, &path);
+        Some(Ok(FsEntry::new(path, md)))
+    }
+}
+
+impl Fs for MemFs {
+    type ReadDir = MemReadDir;
+
+    fn read_dir(&self, path: &Path) -> io::Result<MemReadDir> {
+        match *try!(self.lookup(path)) {
+            Tree::Dir(_, ref children) => {
+                Ok(MemReadDir { base: path.to_path_buf(), entries: children.clone(), pos: 0 })
+            }
+            _ => Err(not_found()),
+        }
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let node = try!(self.lookup(path));
+        match *node {
+            Tree::Symlink(ref src, _) => {
+                let parent = path.parent().unwrap_or(path);
+                self.metadata(&parent.join(src))
+            }
+            _ => Ok(mem_metadata(node, path)),
+        }
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let node = try!(self.lookup(path));
+        Ok(mem_metadata(node, path))
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        match *try!(self.lookup(path)) {
+            Tree::Symlink(ref src, _) => Ok(src.clone()),
+            _ => Err(not_found()),
+        }
+    }
+}
+
 impl Arbitrary for Tree {
     fn arbitrary<G: Gen>(g: &mut G) -> Tree {
         let depth = g.gen_range(0, 5);
@@ -187,7 +408,11 @@ impl Arbitrary for Tree {
 
     fn shrink(&self) -> Box<Iterator<Item=Tree>> {
         let trees: Box<Iterator<Item=Tree>> = match *self {
-            Tree::Symlink(_, _) => unimplemented!(),
+            Tree::Symlink(ref src, ref dst) => {
+                let src = src.clone();
+                let s = dst.to_string_lossy().into_owned();
+                Box::new(s.shrink().map(move |s| Tree::Symlink(src.clone(), pb(s))))
+            }
             Tree::File(ref path) => {
                 let s = path.to_string_lossy().into_owned();
                 Box::new(s.shrink().map(|s| Tree::File(pb(s))))
@@ -244,54 +469,6 @@ impl fmt::Debug for Tree {
     }
 }
 
-enum WalkEvent {
-    Dir(DirEntry),
-    File(DirEntry),
-    Exit,
-}
-
-struct WalkEventIter {
-    depth: usize,
-    it: WalkDirIter,
-    next: Option<Result<DirEntry, WalkDirError>>,
-}
-
-impl<P: AsRef<Path>> From<WalkDir<P>> for WalkEventIter {
-    fn from(it: WalkDir<P>) -> WalkEventIter {
-        WalkEventIter { depth: 0, it: it.into_iter(), next: None }
-    }
-}
-
-impl Iterator for WalkEventIter {
-    type Item = io::Result<WalkEvent>;
-
-    fn next(&mut self) -> Option<io::Result<WalkEvent>> {
-        let dent = self.next.take().or_else(|| self.it.next());
-        if self.it.depth() < self.depth {
-            self.depth -= 1;
-            self.next = dent;
-            return Some(Ok(WalkEvent::Exit));
-        }
-        match dent {
-            None => None,
-            Some(Err(err)) => Some(Err(From::from(err))),
-            Some(Ok(dent)) => {
-                match dent.file_type() {
-                    Err(err) => Some(Err(err)),
-                    Ok(ty) => {
-                        if ty.is_dir() {
-                            self.depth += 1;
-                            Some(Ok(WalkEvent::Dir(dent)))
-                        } else {
-                            Some(Ok(WalkEvent::File(dent)))
-                        }
-                    }
-                }
-            }
-        }
-    }
-}
-
 struct TempDir(PathBuf);
 
 impl TempDir {
@@ -422,6 +599,99 @@ fn walk_dir_7() {
     assert_tree_eq!(exp, got);
 }
 
+#[test]
+fn walk_dir_contents_first() {
+    let exp = td("foo", vec![
+        td("bar", vec![
+           tf("baz"), td("bat", vec![]),
+        ]),
+        tf("quux"),
+    ]);
+    let tmp = tmpdir();
+    exp.create_in(tmp.path()).unwrap();
+
+    let ents = WalkDir::new(tmp.path())
+                        .contents_first(true)
+                        .into_iter()
+                        .collect::<Result<Vec<_>, _>>()
+                        .unwrap();
+    for (i, parent) in ents.iter().enumerate() {
+        if !parent.file_type().unwrap().is_dir() {
+            continue;
+        }
+        for child in &ents[i + 1..] {
+            assert!(!child.path().starts_with(parent.path()),
+                    "{} (a descendant of {}) was yielded after it",
+                    child.path().display(), parent.path().display());
+        }
+    }
+}
+
+#[test]
+#[should_panic(expected = "contents_first")]
+fn walk_dir_contents_first_event_iter_rejected() {
+    let tmp = tmpdir();
+    WalkDir::new(tmp.path()).contents_first(true).into_event_iter();
+}
+
+#[test]
+#[should_panic(expected = "contents_first")]
+fn walk_dir_contents_first_threads_rejected() {
+    let tmp = tmpdir();
+    WalkDir::new(tmp.path()).contents_first(true).threads(4);
+}
+
+#[test]
+#[cfg(unix)]
+fn walk_dir_entry_ext_ino() {
+    let exp = td("foo", vec![tf("bar")]);
+    let tmp = tmpdir();
+    exp.create_in(tmp.path()).unwrap();
+
+    let first = WalkDir::new(tmp.path())
+                        .into_iter()
+                        .collect::<Result<Vec<_>, _>>()
+                        .unwrap();
+    let second = WalkDir::new(tmp.path())
+                         .into_iter()
+                         .collect::<Result<Vec<_>, _>>()
+                         .unwrap();
+    let bar1 = first.iter().find(|e| e.file_name() == "bar").unwrap();
+    let bar2 = second.iter().find(|e| e.file_name() == "bar").unwrap();
+    assert_eq!(bar1.ino(), bar2.ino());
+}
+
+#[test]
+fn walk_dir_sort_by_file_name() {
+    let exp = td("foo", vec![
+        td("bar", vec![
+           tf("baz"), td("bat", vec![]),
+        ]),
+        td("a", vec![tf("b"), tf("c"), tf("d")]),
+    ]);
+    let tmp = tmpdir();
+    exp.create_in(tmp.path()).unwrap();
+
+    let ents = WalkDir::new(tmp.path())
+                        .sort_by_file_name()
+                        .into_iter()
+                        .collect::<Result<Vec<_>, _>>()
+                        .unwrap();
+
+    let mut by_parent: HashMap<PathBuf, Vec<&DirEntry>> = HashMap::new();
+    for e in &ents {
+        by_parent.entry(e.path().parent().unwrap().to_path_buf())
+                 .or_insert_with(Vec::new)
+                 .push(e);
+    }
+    for siblings in by_parent.values() {
+        let names: Vec<_> = siblings.iter().map(|e| e.file_name()).collect();
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(names, sorted);
+    }
+}
+
 #[test]
 #[cfg(unix)]
 fn walk_dir_sym_1() {
@@ -508,6 +778,29 @@ fn walk_dir_sym_infinite() {
     }
 }
 
+#[test]
+#[cfg(unix)]
+fn walk_dir_sym_detect_loop_parallel() {
+    let actual = td("foo", vec![
+        td("a", vec![tl("../b", "blink"), tf("a1"), tf("a2")]),
+        td("b", vec![tl("../a", "alink")]),
+    ]);
+    let tmp = tmpdir();
+    actual.create_in(tmp.path()).unwrap();
+    let got = WalkDir::new(tmp.path())
+                      .follow_links(true)
+                      .threads(4)
+                      .into_iter()
+                      .collect::<Result<Vec<_>, _>>();
+    match got {
+        Ok(x) => panic!("expected loop error, got no error: {:?}", x),
+        Err(WalkDirError::Io { .. }) => {
+            panic!("expected loop error, got generic IO error");
+        }
+        Err(WalkDirError::Loop { .. }) => {}
+    }
+}
+
 #[test]
 fn qc_roundtrip() {
     fn p(exp: Tree) -> bool {
@@ -519,4 +812,55 @@ fn qc_roundtrip() {
                .tests(1_000)
                .max_tests(10_000)
                .quickcheck(p as fn(Tree) -> bool);
+}
+
+#[test]
+fn qc_roundtrip_parallel() {
+    fn p(exp: Tree) -> bool {
+        let tmp = tmpdir();
+        exp.create_in(tmp.path()).unwrap();
+        let got = Tree::from_parallel_walk(tmp.path(), 4).unwrap();
+        exp.canonical() == got.unwrap_singleton().canonical()
+    }
+    QuickCheck::new()
+               .gen(StdGen::new(rand::thread_rng(), 15))
+               .tests(1_000)
+               .max_tests(10_000)
+               .quickcheck(p as fn(Tree) -> bool);
+}
+
+#[test]
+fn qc_roundtrip_memfs() {
+    fn p(exp: Tree) -> bool {
+        let root = PathBuf::from("/mem");
+        let mem_root = Tree::Dir(root.clone(), vec![exp.clone()]);
+        let fs = MemFs::new(root.clone(), mem_root);
+        let got = Tree::from_entries(
+            &root, WalkDir::with_fs(&root, fs.clone()).into_iter(), &fs).unwrap();
+        exp.canonical() == got.unwrap_singleton().canonical()
+    }
+    QuickCheck::new()
+               .gen(StdGen::new(rand::thread_rng(), 15))
+               .tests(1_000)
+               .max_tests(10_000)
+               .quickcheck(p as fn(Tree) -> bool);
+}
+
+#[test]
+fn qc_filter_entry_prunes_subtrees() {
+    const MAX_DEPTH: usize = 2;
+
+    fn p(exp: Tree) -> bool {
+        let tmp = tmpdir();
+        exp.create_in(tmp.path()).unwrap();
+        let got = Tree::from_filtered_walk(tmp.path(), |dent| dent.depth() < MAX_DEPTH)
+                       .unwrap();
+        exp.pruned_at_depth(MAX_DEPTH, 1).canonical()
+            == got.unwrap_singleton().canonical()
+    }
+    QuickCheck::new()
+               .gen(StdGen::new(rand::thread_rng(), 15))
+               .tests(1_000)
+               .max_tests(10_000)
+               .quickcheck(p as fn(Tree) -> bool);
 }
\ No newline at end of file